@@ -1,14 +1,84 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+use arc_swap::ArcSwap;
 use clap::{CommandFactory, Parser};
 use hickory_proto::op::{Header, ResponseCode};
-use hickory_proto::rr::rdata::CNAME;
+use hickory_proto::rr::rdata::{A, AAAA, CNAME};
 use hickory_proto::rr::{LowerName, Name, RData, Record, RecordType};
+use hickory_proto::xfer::Protocol;
+use hickory_resolver::config::{NameServerConfig, ResolverConfig};
+use hickory_resolver::name_server::TokioConnectionProvider;
+use hickory_resolver::Resolver;
 use hickory_server::authority::MessageResponseBuilder;
 use hickory_server::server::{Request, RequestHandler, ResponseHandler, ResponseInfo, ServerFuture};
+use lru::LruCache;
 use serde::{Deserialize, Deserializer};
-use tokio::net::UdpSocket;
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::Mutex;
+
+const fn default_true() -> bool {
+    true
+}
+
+const fn default_tcp_timeout_secs() -> u64 {
+    5
+}
+
+const fn default_cache_max_entries() -> usize {
+    1024
+}
+
+const fn default_max_chain_depth() -> u32 {
+    8
+}
+
+const BASE_TTL: u32 = 300;
+
+/// A probe name used at config-load time to detect a rule whose `to` simply
+/// echoes back whatever trivially matched `from` (e.g. `^(.*)$ -> {1}`).
+const SELF_REFERENCE_PROBE: &str = "cycle-check.invalid.";
+
+fn clamp_ttl(ttl: u32, min_ttl: Option<u32>, max_ttl: Option<u32>) -> u32 {
+    let ttl = min_ttl.map_or(ttl, |min| ttl.max(min));
+    max_ttl.map_or(ttl, |max| ttl.min(max))
+}
+
+/// Renders `replacement.to` against `name` if `replacement.from` matches, substituting
+/// capture groups. Returns `None` if `from` doesn't match, or `Some(Err(_))` if `to`
+/// references a capture group `strfmt` can't resolve.
+fn render_replacement(replacement: &Replacement, name: &str) -> Option<Result<String>> {
+    replacement.from.captures(name).map(|caps| {
+        let vars: HashMap<String, String> = caps.iter().enumerate().fold(HashMap::new(), |mut map, (index, cap)| {
+            map.insert(index.to_string(), cap.map_or_else(String::new, |c| c.as_str().to_string()));
+            map
+        });
+        strfmt::strfmt(&replacement.to, &vars)
+            .map_err(|e| anyhow::anyhow!("{e}"))
+            .with_context(|| format!("failed to render replacement '{}' -> '{}'", replacement.from, replacement.to))
+    })
+}
+
+/// Rejects any rule that would rewrite `SELF_REFERENCE_PROBE` back to itself,
+/// e.g. `^(.*)$ -> {1}`, which would otherwise alias every name to itself.
+fn validate_replacements(replacements: &[Replacement]) -> Result<()> {
+    for replacement in replacements {
+        if let Some(rendered) = render_replacement(replacement, SELF_REFERENCE_PROBE) {
+            if rendered? == SELF_REFERENCE_PROBE {
+                anyhow::bail!(
+                    "replacement rule '{}' -> '{}' rewrites a trivially-matching name back to itself",
+                    replacement.from,
+                    replacement.to
+                );
+            }
+        }
+    }
+    Ok(())
+}
 
 
 fn deserialize_regex<'de, D>(deserializer: D) -> Result<regex::Regex, D::Error>
@@ -19,11 +89,22 @@ where
     regex::Regex::new(&s).map_err(serde::de::Error::custom)
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum RecordKind {
+    #[default]
+    Cname,
+    A,
+    Aaaa,
+}
+
 #[derive(Clone, Deserialize)]
 struct Replacement {
     #[serde(deserialize_with = "deserialize_regex")]
     from: regex::Regex,
     to: String,
+    #[serde(default)]
+    record: RecordKind,
 }
 
 
@@ -31,36 +112,284 @@ struct Replacement {
 struct Config {
     bind_address: String,
     replacements: Vec<Replacement>,
+    #[serde(default = "default_true")]
+    enable_tcp: bool,
+    #[serde(default = "default_tcp_timeout_secs")]
+    tcp_timeout_secs: u64,
+    #[serde(default)]
+    upstream_servers: Vec<String>,
+    #[serde(default)]
+    pid_file: Option<String>,
+    #[serde(default = "default_cache_max_entries")]
+    cache_max_entries: usize,
+    #[serde(default)]
+    min_ttl: Option<u32>,
+    #[serde(default)]
+    max_ttl: Option<u32>,
+    #[serde(default)]
+    negative_ttl: Option<u32>,
+    #[serde(default = "default_max_chain_depth")]
+    max_chain_depth: u32,
 }
 
 impl Config {
     fn load_from_json(json: &str) -> Result<Self> {
-        serde_json::from_str(json).map_err(|e| e.into())
+        let config: Self = serde_json::from_str(json)?;
+        validate_replacements(&config.replacements)?;
+        Ok(config)
+    }
+
+    fn load_from_yaml(yaml: &str) -> Result<Self> {
+        let config: Self = serde_yaml::from_str(yaml)?;
+        validate_replacements(&config.replacements)?;
+        Ok(config)
+    }
+
+    /// Loads the config from `path`, picking JSON or YAML by file extension,
+    /// then layers `DNS_REDIRECT_*` environment variables on top.
+    fn load_from_file(path: &str) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        let mut config = match std::path::Path::new(path).extension().and_then(|ext| ext.to_str()) {
+            Some("yaml" | "yml") => Self::load_from_yaml(&contents)?,
+            _ => Self::load_from_json(&contents)?,
+        };
+        config.apply_env_overrides()?;
+        Ok(config)
+    }
+
+    fn apply_env_overrides(&mut self) -> Result<()> {
+        if let Ok(bind_address) = std::env::var("DNS_REDIRECT_BIND_ADDRESS") {
+            self.bind_address = bind_address;
+        }
+
+        if self.bind_address.is_empty() {
+            anyhow::bail!("bind_address is required after merging config file and environment overrides");
+        }
+
+        Ok(())
+    }
+
+    #[cfg(test)]
+    fn new(bind_address: String, replacements: Vec<Replacement>) -> Self {
+        Self {
+            bind_address,
+            replacements,
+            enable_tcp: default_true(),
+            tcp_timeout_secs: default_tcp_timeout_secs(),
+            upstream_servers: Vec::new(),
+            pid_file: None,
+            cache_max_entries: default_cache_max_entries(),
+            min_ttl: None,
+            max_ttl: None,
+            negative_ttl: None,
+            max_chain_depth: default_max_chain_depth(),
+        }
     }
 }
 
+fn load_replacements(config_file: &str) -> Result<Vec<Replacement>> {
+    Ok(Config::load_from_file(config_file)?.replacements)
+}
+
+fn write_pid_file(path: &str) -> Result<()> {
+    std::fs::write(path, std::process::id().to_string())?;
+    Ok(())
+}
+
+
+fn build_upstream_resolver(upstream_servers: &[String]) -> Result<Option<Resolver<TokioConnectionProvider>>> {
+    if upstream_servers.is_empty() {
+        return Ok(None);
+    }
+
+    let mut resolver_config = ResolverConfig::new();
+    for server in upstream_servers {
+        let address = server.parse()?;
+        resolver_config.add_name_server(NameServerConfig::new(address, Protocol::Udp));
+    }
+
+    Ok(Some(
+        Resolver::builder_with_config(resolver_config, TokioConnectionProvider::default()).build(),
+    ))
+}
+
+#[derive(Clone)]
+enum CachedAnswer {
+    Records(Vec<Record>),
+    NxDomain,
+}
+
+/// Outcome of following a replacement chain to its end.
+enum ChainResult {
+    /// The chain terminated in a name with no further replacement; `records`
+    /// holds one CNAME per hop and `final_name` is the last alias, which may
+    /// still need resolving upstream for the requested type.
+    Cname { records: Vec<Record>, final_name: Name },
+    /// The chain terminated in an `a`/`aaaa` rule matching the requested type;
+    /// `records` holds the CNAME hops (if any) plus the final address record.
+    Answer(Vec<Record>),
+    /// No replacement matched `name`, or the chain ended in an `a`/`aaaa` rule
+    /// whose record type differs from the requested type.
+    NoMatch,
+}
+
+struct CacheEntry {
+    answer: CachedAnswer,
+    expires_at: Instant,
+}
 
 #[derive(Clone)]
 struct DomainConversionHandler {
-    replacements: Vec<Replacement>,
+    replacements: Arc<ArcSwap<Vec<Replacement>>>,
+    upstream_resolver: Option<Resolver<TokioConnectionProvider>>,
+    cache: Arc<Mutex<LruCache<(LowerName, RecordType), CacheEntry>>>,
+    min_ttl: Option<u32>,
+    max_ttl: Option<u32>,
+    negative_ttl: Option<u32>,
+    max_chain_depth: u32,
 }
 
 impl DomainConversionHandler {
-    const fn new(replacements: Vec<Replacement>) -> Self {
-        Self { replacements }
-    }
-
-    fn find_replacement(&self, name: &LowerName) -> Option<String> {
-        self.replacements.iter().find_map(|replacement| {
-            replacement.from.captures(&name.to_utf8()).map(|caps| {
-                let vars: HashMap<String, String> = caps.iter().enumerate().fold(HashMap::new(), |mut map, (index, cap)| {
-                    map.insert(index.to_string(), cap.map_or_else(String::new, |c| c.as_str().to_string()));
-                    map
-                });
-                strfmt::strfmt(&replacement.to, &vars).unwrap()
-            })
+    fn new(
+        replacements: Vec<Replacement>,
+        upstream_resolver: Option<Resolver<TokioConnectionProvider>>,
+        cache_max_entries: usize,
+        min_ttl: Option<u32>,
+        max_ttl: Option<u32>,
+        negative_ttl: Option<u32>,
+        max_chain_depth: u32,
+    ) -> Self {
+        let capacity = NonZeroUsize::new(cache_max_entries).unwrap_or(NonZeroUsize::new(1).unwrap());
+        Self {
+            replacements: Arc::new(ArcSwap::from_pointee(replacements)),
+            upstream_resolver,
+            cache: Arc::new(Mutex::new(LruCache::new(capacity))),
+            min_ttl,
+            max_ttl,
+            negative_ttl,
+            max_chain_depth,
+        }
+    }
+
+    /// Atomically replaces the active replacement rules, e.g. on a SIGHUP reload,
+    /// and clears the response cache so the new rules take effect immediately
+    /// instead of waiting out the TTL of whatever is already cached.
+    async fn reload(&self, replacements: Vec<Replacement>) {
+        self.replacements.store(Arc::new(replacements));
+        self.cache.lock().await.clear();
+    }
+
+    async fn cache_lookup(&self, key: &(LowerName, RecordType)) -> Option<CachedAnswer> {
+        let mut cache = self.cache.lock().await;
+        if let Some(entry) = cache.get(key) {
+            if entry.expires_at > Instant::now() {
+                return Some(entry.answer.clone());
+            }
+            cache.pop(key);
+        }
+        None
+    }
+
+    async fn cache_insert(&self, key: (LowerName, RecordType), answer: CachedAnswer, ttl_secs: u32) {
+        let mut cache = self.cache.lock().await;
+        cache.put(
+            key,
+            CacheEntry {
+                answer,
+                expires_at: Instant::now() + Duration::from_secs(ttl_secs.into()),
+            },
+        );
+    }
+
+    fn find_replacement(&self, name: &LowerName) -> Option<(String, RecordKind)> {
+        let name = name.to_utf8();
+        self.replacements.load().iter().find_map(|replacement| {
+            match render_replacement(replacement, &name) {
+                Some(Ok(value)) => Some((value, replacement.record)),
+                Some(Err(e)) => {
+                    eprintln!("Skipping unrenderable replacement '{}' -> '{}': {e}", replacement.from, replacement.to);
+                    None
+                }
+                None => None,
+            }
         })
     }
+
+    /// Follows the replacement chain starting at `name`, collecting the CNAME
+    /// record for each hop, until a name has no further replacement or a rule
+    /// terminates the chain with a direct `a`/`aaaa` answer. Stops with
+    /// `Err(())` (reported to the client as SERVFAIL) if a name repeats or the
+    /// chain exceeds `max_chain_depth`, so a cyclic or self-referential rule
+    /// set can't make the server loop forever.
+    fn resolve_chain(&self, name: &LowerName, max_chain_depth: u32, query_type: RecordType) -> Result<ChainResult, ()> {
+        let Some((first_target, first_kind)) = self.find_replacement(name) else {
+            return Ok(ChainResult::NoMatch);
+        };
+
+        let ttl = clamp_ttl(BASE_TTL, self.min_ttl, self.max_ttl);
+        let mut visited: HashSet<LowerName> = HashSet::new();
+        visited.insert(name.clone());
+
+        let mut records = Vec::new();
+        let mut current_name: Name = name.into();
+        let mut current_target = first_target;
+        let mut current_kind = first_kind;
+
+        loop {
+            if records.len() as u32 >= max_chain_depth {
+                return Err(());
+            }
+
+            match current_kind {
+                RecordKind::A | RecordKind::Aaaa => {
+                    let expected_type = if current_kind == RecordKind::A { RecordType::A } else { RecordType::AAAA };
+                    if query_type != expected_type && query_type != RecordType::ANY {
+                        return Ok(ChainResult::NoMatch);
+                    }
+
+                    let rdata = match current_target.parse::<std::net::IpAddr>().map_err(|_| ())? {
+                        std::net::IpAddr::V4(addr) => RData::A(A(addr)),
+                        std::net::IpAddr::V6(addr) => RData::AAAA(AAAA(addr)),
+                    };
+                    records.push(Record::from_rdata(current_name, ttl, rdata));
+                    return Ok(ChainResult::Answer(records));
+                }
+                RecordKind::Cname => {
+                    let target_name = Name::from_utf8(&current_target).map_err(|_| ())?;
+                    let target_lower = LowerName::from(&target_name);
+                    if !visited.insert(target_lower.clone()) {
+                        return Err(());
+                    }
+
+                    records.push(Record::from_rdata(
+                        current_name,
+                        ttl,
+                        RData::CNAME(CNAME(target_name.clone())),
+                    ));
+
+                    match self.find_replacement(&target_lower) {
+                        Some((next_target, next_kind)) => {
+                            current_name = target_name;
+                            current_target = next_target;
+                            current_kind = next_kind;
+                        }
+                        None => return Ok(ChainResult::Cname { records, final_name: target_name }),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Resolves `name` upstream for `record_type` (A or AAAA only), returning the
+    /// resolved address records, or `None` if no upstream resolver is configured.
+    async fn resolve_upstream(&self, name: &Name, record_type: RecordType) -> Result<Option<Vec<Record>>, ()> {
+        let Some(resolver) = &self.upstream_resolver else {
+            return Ok(None);
+        };
+
+        let lookup = resolver.lookup(name.clone(), record_type).await.map_err(|_| ())?;
+        Ok(Some(lookup.records().to_vec()))
+    }
 }
 
 #[async_trait::async_trait]
@@ -72,32 +401,82 @@ impl RequestHandler for DomainConversionHandler {
         mut response_handle: R,
     ) -> ResponseInfo {
         // Check if the first query matches something we can handle
-        let record =  if let Some(query) = request.queries().first() {
-            match query.query_type() {
-                RecordType::A | RecordType::AAAA | RecordType::ANY => {
-                    // Try to match the name against a replacement
-                    let new_value = self.find_replacement(query.name());
-                    new_value.map( |value| {
-                        // Respond with a CNAME record pointing to the new value
-                        Record::from_rdata(
-                            query.name().into(),
-                            300,
-                            RData::CNAME(CNAME(Name::from_utf8(value).unwrap())),
-                        )
-                    })
-                },
-                _ => None,
+        let query_type = request.queries().first().map(|query| query.query_type());
+        let records = match query_type {
+            Some(RecordType::A | RecordType::AAAA | RecordType::ANY) => {
+                let query = request.queries().first().unwrap();
+                let cache_key = (query.name().clone(), query.query_type());
+
+                if let Some(cached) = self.cache_lookup(&cache_key).await {
+                    match cached {
+                        CachedAnswer::Records(records) => Some(records),
+                        CachedAnswer::NxDomain => None,
+                    }
+                } else {
+                    // Try to match the name against a replacement, following any
+                    // chain of replacements until it terminates
+                    match self.resolve_chain(query.name(), self.max_chain_depth, query.query_type()) {
+                        Ok(ChainResult::Answer(records)) => {
+                            let ttl = clamp_ttl(BASE_TTL, self.min_ttl, self.max_ttl);
+                            self.cache_insert(cache_key, CachedAnswer::Records(records.clone()), ttl).await;
+                            Some(records)
+                        }
+                        Ok(ChainResult::Cname { records: chain_records, final_name }) => {
+                            let ttl = clamp_ttl(BASE_TTL, self.min_ttl, self.max_ttl);
+
+                            // Only resolve A/AAAA upstream; ANY keeps the bare CNAME chain as before
+                            let addresses = match query.query_type() {
+                                RecordType::A | RecordType::AAAA => {
+                                    self.resolve_upstream(&final_name, query.query_type()).await
+                                }
+                                _ => Ok(None),
+                            };
+
+                            match addresses {
+                                Ok(Some(address_records)) => {
+                                    let mut records = chain_records;
+                                    records.extend(address_records);
+                                    self.cache_insert(cache_key, CachedAnswer::Records(records.clone()), ttl).await;
+                                    Some(records)
+                                }
+                                Ok(None) => {
+                                    self.cache_insert(cache_key, CachedAnswer::Records(chain_records.clone()), ttl).await;
+                                    Some(chain_records)
+                                }
+                                Err(()) => {
+                                    // Upstream resolution failed: let the caller know via SERVFAIL
+                                    // rather than returning a dangling CNAME.
+                                    let mr = MessageResponseBuilder::from_message_request(request)
+                                        .error_msg(request.header(), ResponseCode::ServFail);
+                                    return response_handle.send_response(mr).await.unwrap();
+                                }
+                            }
+                        }
+                        Ok(ChainResult::NoMatch) => {
+                            if let Some(negative_ttl) = self.negative_ttl {
+                                self.cache_insert(cache_key, CachedAnswer::NxDomain, negative_ttl).await;
+                            }
+                            None
+                        }
+                        Err(()) => {
+                            // Cyclic or over-long replacement chain: don't hand the
+                            // resolver a dangling/looping alias.
+                            let mr = MessageResponseBuilder::from_message_request(request)
+                                .error_msg(request.header(), ResponseCode::ServFail);
+                            return response_handle.send_response(mr).await.unwrap();
+                        }
+                    }
+                }
             }
-        } else {
-            None
+            _ => None,
         };
 
         // Send the response
-        if record.is_some() {
-            let rec = record.unwrap();
+        if let Some(records) = records {
+            let refs: Vec<&Record> = records.iter().collect();
             let mr = MessageResponseBuilder::from_message_request(request).build(
                 Header::response_from_request(request.header()),
-                vec![&rec],
+                refs,
                 vec![],
                 vec![],
                 vec![],
@@ -115,14 +494,62 @@ impl RequestHandler for DomainConversionHandler {
 
 }
 
-async fn create_server(config: Config) -> Result<ServerFuture<DomainConversionHandler>, Box<dyn std::error::Error>> {
+/// Watches for SIGHUP and re-reads `config_file`, swapping the new replacement
+/// rules into `handler`. Reload errors are logged and the previous rules kept.
+fn spawn_reload_task(handler: DomainConversionHandler, config_file: String) {
+    tokio::spawn(async move {
+        let mut sighup = match signal(SignalKind::hangup()) {
+            Ok(sighup) => sighup,
+            Err(e) => {
+                eprintln!("Failed to install SIGHUP handler: {e}");
+                return;
+            }
+        };
+
+        loop {
+            sighup.recv().await;
+            match load_replacements(&config_file) {
+                Ok(replacements) => {
+                    handler.reload(replacements).await;
+                    println!("Reloaded replacements from {config_file}");
+                }
+                Err(e) => eprintln!("Failed to reload {config_file}, keeping previous rules: {e}"),
+            }
+        }
+    });
+}
+
+async fn create_server(config: Config, config_file: String) -> Result<ServerFuture<DomainConversionHandler>, Box<dyn std::error::Error>> {
     // Bind to UDP port 8053 (you can change this)
-    let socket = UdpSocket::bind(config.bind_address).await?;
+    let socket = UdpSocket::bind(&config.bind_address).await?;
+
+    let upstream_resolver = build_upstream_resolver(&config.upstream_servers)
+        .map_err(|e| Box::<dyn std::error::Error>::from(e.to_string()))?;
+
+    if let Some(pid_file) = &config.pid_file {
+        write_pid_file(pid_file)?;
+    }
+
+    let handler = DomainConversionHandler::new(
+        config.replacements,
+        upstream_resolver,
+        config.cache_max_entries,
+        config.min_ttl,
+        config.max_ttl,
+        config.negative_ttl,
+        config.max_chain_depth,
+    );
+    spawn_reload_task(handler.clone(), config_file);
 
     // Create a server
-    let mut server = ServerFuture::new(DomainConversionHandler::new(config.replacements));
+    let mut server = ServerFuture::new(handler);
     server.register_socket(socket);
 
+    if config.enable_tcp {
+        let listener = TcpListener::bind(&config.bind_address).await?;
+        server.register_listener(listener, Duration::from_secs(config.tcp_timeout_secs));
+    }
+
     Ok(server)
 }
 
@@ -144,14 +571,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>>{
     println!("");
     println!("Using config file: {}", args.config_file);
     println!("");
-    let json = std::fs::read_to_string(args.config_file)?;
-    let config = Config::load_from_json(&json)?;
+    let config = Config::load_from_file(&args.config_file)?;
 
     println!("");
     println!("Starting server on {} ...", &config.bind_address);
     println!("");
 
-    let mut server = create_server(config).await?;
+    let mut server = create_server(config, args.config_file.clone()).await?;
 
     println!("");
     println!("Server Running on ...");
@@ -184,10 +610,10 @@ mod tests {
         Ok(port)
     }
 
-    fn setup_resolver(server_address: &str) -> Resolver<TokioConnectionProvider> {
+    fn setup_resolver(server_address: &str, protocol: Protocol) -> Resolver<TokioConnectionProvider> {
         let name_server_config = NameServerConfig::new(
             SocketAddr::from_str(server_address).unwrap(),
-            Protocol::Udp,
+            protocol,
         );
 
         let mut resolver_config = ResolverConfig::new();
@@ -206,9 +632,11 @@ mod tests {
 
     async fn test_server(replacements: Vec<Replacement>, test_cases: Vec<(&str, &str)>) {
         let address = format!("127.0.0.1:{}", find_free_port().unwrap());
-        let mut server = create_server(Config::new(address.clone(), replacements)).await.unwrap();
+        let mut server = create_server(Config::new(address.clone(), replacements), "test-config.json".to_string())
+            .await
+            .unwrap();
 
-        let resolver = setup_resolver(&address);
+        let resolver = setup_resolver(&address, Protocol::Udp);
 
         let stream = stream::iter(test_cases.iter());
 
@@ -247,6 +675,7 @@ mod tests {
             Replacement {
                 from: regex::Regex::new(r"^.*$").unwrap(),
                 to: "bob.lan.".to_string(),
+                record: RecordKind::Cname,
             }
         ],
         vec![
@@ -262,6 +691,7 @@ mod tests {
             Replacement {
                 from: regex::Regex::new(r"^(.*)\.mnh.?$").unwrap(),
                 to: "{1}.lan.".to_string(),
+                record: RecordKind::Cname,
             }
         ],
         vec![
@@ -277,10 +707,12 @@ mod tests {
             Replacement {
                 from: regex::Regex::new(r"^(.*)\.mnh.?$").unwrap(),
                 to: "{1}.lan.".to_string(),
+                record: RecordKind::Cname,
             },
             Replacement {
                 from: regex::Regex::new(r"^(.*)\.(.*)\.pod.?$").unwrap(),
                 to: "{2}.{1}.pod.".to_string(),
+                record: RecordKind::Cname,
             },
         ],
         vec![
@@ -294,14 +726,18 @@ mod tests {
     #[tokio::test]
     async fn test_no_match_returns_nxdomain() {
         let address = format!("127.0.0.1:{}", find_free_port().unwrap());
-        let mut server = create_server(Config::new(address.clone(), vec![
-            Replacement {
-                from: regex::Regex::new(r"^(.*)\.mnh.?$").unwrap(),
-                to: "dont.care.".to_string(),
-            }
-        ])).await.unwrap();
+        let mut server = create_server(
+            Config::new(address.clone(), vec![
+                Replacement {
+                    from: regex::Regex::new(r"^(.*)\.mnh.?$").unwrap(),
+                    to: "dont.care.".to_string(),
+                    record: RecordKind::Cname,
+                }
+            ]),
+            "test-config.json".to_string(),
+        ).await.unwrap();
 
-        let resolver = setup_resolver(&address);
+        let resolver = setup_resolver(&address, Protocol::Udp);
 
         let lookup_result = resolver.lookup(Name::from_utf8("barry.net").unwrap(), RecordType::ANY).await;
 
@@ -317,14 +753,18 @@ mod tests {
     #[tokio::test]
     async fn test_wrong_query_type_returns_nxdomain() {
         let address = format!("127.0.0.1:{}", find_free_port().unwrap());
-        let mut server = create_server(Config::new(address.clone(), vec![
-            Replacement {
-                from: regex::Regex::new(r"^(.*)\.net.?$").unwrap(),
-                to: "dont.care.".to_string(),
-            }
-        ])).await.unwrap();
+        let mut server = create_server(
+            Config::new(address.clone(), vec![
+                Replacement {
+                    from: regex::Regex::new(r"^(.*)\.net.?$").unwrap(),
+                    to: "dont.care.".to_string(),
+                    record: RecordKind::Cname,
+                }
+            ]),
+            "test-config.json".to_string(),
+        ).await.unwrap();
 
-        let resolver = setup_resolver(&address);
+        let resolver = setup_resolver(&address, Protocol::Udp);
 
         let lookup_result = resolver.lookup(Name::from_utf8("barry.net").unwrap(), RecordType::CSYNC).await;
 
@@ -382,4 +822,272 @@ mod tests {
         assert_eq!(config.replacements[0].to, "{1}.lan.");
     }
 
+    #[tokio::test]
+    async fn test_tcp_transport_returns_expected_cname() {
+        let address = format!("127.0.0.1:{}", find_free_port().unwrap());
+        let mut server = create_server(
+            Config::new(address.clone(), vec![
+                Replacement {
+                    from: regex::Regex::new(r"^.*\.mnh.?$").unwrap(),
+                    to: "bob.lan.".to_string(),
+                    record: RecordKind::Cname,
+                }
+            ]),
+            "test-config.json".to_string(),
+        ).await.unwrap();
+
+        let resolver = setup_resolver(&address, Protocol::Tcp);
+        let result = resolver.lookup(Name::from_utf8("bob.mnh").unwrap(), RecordType::ANY).await.unwrap();
+
+        server.shutdown_gracefully().await.unwrap();
+
+        let is_matching_cname = |record: &Record| {
+            matches!(record.data(), RData::CNAME(cname) if cname.to_string() == "bob.lan.")
+        };
+        assert!(result.records().iter().any(is_matching_cname), "Didn't find bob.lan. in the cnames over TCP");
+    }
+
+    #[tokio::test]
+    async fn test_upstream_resolution_returns_cname_and_address() {
+        // Acts as the upstream: answers any A query directly with a literal address.
+        let upstream_address = format!("127.0.0.1:{}", find_free_port().unwrap());
+        let mut upstream_server = create_server(
+            Config::new(upstream_address.clone(), vec![
+                Replacement {
+                    from: regex::Regex::new(r"^.*$").unwrap(),
+                    to: "203.0.113.5".to_string(),
+                    record: RecordKind::A,
+                }
+            ]),
+            "test-config.json".to_string(),
+        ).await.unwrap();
+
+        let mut config = Config::new(format!("127.0.0.1:{}", find_free_port().unwrap()), vec![
+            Replacement {
+                from: regex::Regex::new(r"^(.*)\.mnh.?$").unwrap(),
+                to: "{1}.up.".to_string(),
+                record: RecordKind::Cname,
+            }
+        ]);
+        config.upstream_servers = vec![upstream_address];
+        let address = config.bind_address.clone();
+        let mut server = create_server(config, "test-config.json".to_string()).await.unwrap();
+
+        let resolver = setup_resolver(&address, Protocol::Udp);
+        let result = resolver.lookup(Name::from_utf8("bob.mnh").unwrap(), RecordType::A).await.unwrap();
+
+        server.shutdown_gracefully().await.unwrap();
+        upstream_server.shutdown_gracefully().await.unwrap();
+
+        let has_cname = result.records().iter().any(|record| {
+            matches!(record.data(), RData::CNAME(cname) if cname.to_string() == "bob.up.")
+        });
+        let has_address = result.records().iter().any(|record| {
+            matches!(record.data(), RData::A(a) if a.0 == std::net::Ipv4Addr::new(203, 0, 113, 5))
+        });
+        assert!(has_cname, "Didn't find the bob.up. CNAME hop");
+        assert!(has_address, "Didn't find the upstream-resolved A record");
+    }
+
+    #[tokio::test]
+    async fn test_upstream_resolution_failure_returns_servfail() {
+        // Nothing listens on this port, so the upstream lookup will fail.
+        let unreachable_upstream = format!("127.0.0.1:{}", find_free_port().unwrap());
+
+        let mut config = Config::new(format!("127.0.0.1:{}", find_free_port().unwrap()), vec![
+            Replacement {
+                from: regex::Regex::new(r"^(.*)\.mnh.?$").unwrap(),
+                to: "{1}.up.".to_string(),
+                record: RecordKind::Cname,
+            }
+        ]);
+        config.upstream_servers = vec![unreachable_upstream];
+        let address = config.bind_address.clone();
+        let mut server = create_server(config, "test-config.json".to_string()).await.unwrap();
+
+        let resolver = setup_resolver(&address, Protocol::Udp);
+        let lookup_result = resolver.lookup(Name::from_utf8("bob.mnh").unwrap(), RecordType::A).await;
+
+        server.shutdown_gracefully().await.unwrap();
+
+        match lookup_result {
+            Ok(res) => panic!("Expected SERVFAIL but got result: {:?}", res),
+            Err(e) => assert!(!e.is_nx_domain(), "Expected SERVFAIL (not NXDOMAIN) but got: {}", e),
+        };
+    }
+
+    #[tokio::test]
+    async fn test_cache_hit_serves_previously_computed_records() {
+        let handler = DomainConversionHandler::new(vec![], None, 10, None, None, None, 8);
+        let key = (LowerName::from(Name::from_utf8("bob.mnh.").unwrap()), RecordType::A);
+
+        assert!(handler.cache_lookup(&key).await.is_none());
+
+        let record = Record::from_rdata(
+            Name::from_utf8("bob.mnh.").unwrap(),
+            300,
+            RData::CNAME(CNAME(Name::from_utf8("bob.lan.").unwrap())),
+        );
+        handler.cache_insert(key.clone(), CachedAnswer::Records(vec![record]), 300).await;
+
+        let cached = handler.cache_lookup(&key).await;
+        assert!(matches!(cached, Some(CachedAnswer::Records(ref records)) if records.len() == 1));
+    }
+
+    #[tokio::test]
+    async fn test_cache_entry_expires_after_ttl() {
+        let handler = DomainConversionHandler::new(vec![], None, 10, None, None, None, 8);
+        let key = (LowerName::from(Name::from_utf8("expired.example.").unwrap()), RecordType::A);
+
+        handler.cache_insert(key.clone(), CachedAnswer::NxDomain, 0).await;
+        tokio::time::sleep(Duration::from_millis(5)).await;
+
+        assert!(handler.cache_lookup(&key).await.is_none(), "expired entry should not be served");
+    }
+
+    #[tokio::test]
+    async fn test_cache_evicts_least_recently_used_entry() {
+        let handler = DomainConversionHandler::new(vec![], None, 1, None, None, None, 8);
+        let key_a = (LowerName::from(Name::from_utf8("a.example.").unwrap()), RecordType::A);
+        let key_b = (LowerName::from(Name::from_utf8("b.example.").unwrap()), RecordType::A);
+
+        handler.cache_insert(key_a.clone(), CachedAnswer::NxDomain, 300).await;
+        handler.cache_insert(key_b.clone(), CachedAnswer::NxDomain, 300).await;
+
+        assert!(handler.cache_lookup(&key_a).await.is_none(), "oldest entry should have been evicted");
+        assert!(handler.cache_lookup(&key_b).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_reload_clears_cache() {
+        let handler = DomainConversionHandler::new(vec![], None, 10, None, None, None, 8);
+        let key = (LowerName::from(Name::from_utf8("bob.mnh.").unwrap()), RecordType::A);
+        handler.cache_insert(key.clone(), CachedAnswer::NxDomain, 300).await;
+        assert!(handler.cache_lookup(&key).await.is_some());
+
+        handler.reload(vec![]).await;
+
+        assert!(handler.cache_lookup(&key).await.is_none(), "reload should invalidate stale cache entries");
+    }
+
+    #[test]
+    fn test_load_from_yaml_file_applies_env_override() {
+        let path = std::env::temp_dir().join(format!("dns-redirect-test-{}.yaml", std::process::id()));
+        std::fs::write(&path, "bind_address: \"127.0.0.1:1053\"\nreplacements:\n  - from: \"^(.*)\\\\.mnh.?$\"\n    to: \"{1}.lan.\"\n").unwrap();
+
+        std::env::set_var("DNS_REDIRECT_BIND_ADDRESS", "127.0.0.1:2053");
+        let result = Config::load_from_file(path.to_str().unwrap());
+        std::env::remove_var("DNS_REDIRECT_BIND_ADDRESS");
+        std::fs::remove_file(&path).unwrap();
+
+        let config = result.unwrap();
+        assert_eq!(config.bind_address, "127.0.0.1:2053");
+        assert_eq!(config.replacements.len(), 1);
+        assert!(config.replacements[0].from.is_match("bob.mnh"));
+    }
+
+    #[test]
+    fn test_validate_replacements_rejects_self_referential_rule() {
+        let json = r#"
+        {
+            "bind_address": "127.0.0.1:1053",
+            "replacements": [
+                {
+                    "from": "^(.*)$",
+                    "to": "{1}"
+                }
+            ]
+        }
+        "#;
+
+        assert!(Config::load_from_json(json).is_err(), "expected a self-referential rule to be rejected at load time");
+    }
+
+    #[tokio::test]
+    async fn test_cyclic_replacement_chain_returns_servfail() {
+        let address = format!("127.0.0.1:{}", find_free_port().unwrap());
+        let mut server = create_server(
+            Config::new(address.clone(), vec![
+                Replacement {
+                    from: regex::Regex::new(r"^a\.cycle.?$").unwrap(),
+                    to: "b.cycle.".to_string(),
+                    record: RecordKind::Cname,
+                },
+                Replacement {
+                    from: regex::Regex::new(r"^b\.cycle.?$").unwrap(),
+                    to: "a.cycle.".to_string(),
+                    record: RecordKind::Cname,
+                },
+            ]),
+            "test-config.json".to_string(),
+        ).await.unwrap();
+
+        let resolver = setup_resolver(&address, Protocol::Udp);
+        let lookup_result = resolver.lookup(Name::from_utf8("a.cycle").unwrap(), RecordType::A).await;
+
+        server.shutdown_gracefully().await.unwrap();
+
+        match lookup_result {
+            Ok(res) => panic!("Expected SERVFAIL but got result: {:?}", res),
+            Err(e) => assert!(!e.is_nx_domain(), "Expected SERVFAIL (not NXDOMAIN) for a cyclic chain: {}", e),
+        };
+    }
+
+    #[tokio::test]
+    async fn test_any_query_against_address_rule_returns_address() {
+        let address = format!("127.0.0.1:{}", find_free_port().unwrap());
+        let mut server = create_server(
+            Config::new(address.clone(), vec![
+                Replacement {
+                    from: regex::Regex::new(r"^.*$").unwrap(),
+                    to: "203.0.113.9".to_string(),
+                    record: RecordKind::A,
+                }
+            ]),
+            "test-config.json".to_string(),
+        ).await.unwrap();
+
+        let resolver = setup_resolver(&address, Protocol::Udp);
+        let result = resolver.lookup(Name::from_utf8("bob.mnh").unwrap(), RecordType::ANY).await.unwrap();
+
+        server.shutdown_gracefully().await.unwrap();
+
+        let has_address = result.records().iter().any(|record| {
+            matches!(record.data(), RData::A(a) if a.0 == std::net::Ipv4Addr::new(203, 0, 113, 9))
+        });
+        assert!(has_address, "Expected an ANY query to still get the address record from an `a` rule");
+    }
+
+    #[test]
+    fn test_write_pid_file_writes_current_process_id() {
+        let path = std::env::temp_dir().join(format!("dns-redirect-test-{}-direct.pid", std::process::id()));
+
+        write_pid_file(path.to_str().unwrap()).unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(contents, std::process::id().to_string());
+    }
+
+    #[tokio::test]
+    async fn test_create_server_writes_pid_file() {
+        let path = std::env::temp_dir().join(format!("dns-redirect-test-{}-server.pid", std::process::id()));
+        let mut config = Config::new(format!("127.0.0.1:{}", find_free_port().unwrap()), vec![
+            Replacement {
+                from: regex::Regex::new(r"^.*$").unwrap(),
+                to: "bob.lan.".to_string(),
+                record: RecordKind::Cname,
+            }
+        ]);
+        config.pid_file = Some(path.to_str().unwrap().to_string());
+
+        let mut server = create_server(config, "test-config.json".to_string()).await.unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        server.shutdown_gracefully().await.unwrap();
+
+        assert_eq!(contents, std::process::id().to_string());
+    }
+
 }